@@ -1,13 +1,18 @@
 //! Types and functions for building TZE transaction components
 #![cfg(feature = "zfuture")]
 
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::marker::PhantomData;
 
 use crate::{
     extensions::transparent::{self as tze, ToPayload},
     transaction::components::{
         amount::Amount,
-        tze::{Bundle, OutPoint, TzeIn, TzeOut, Unauthorized},
+        tze::{
+            fees::{self, FeeRule},
+            Authorized, Bundle, OutPoint, TzeIn, TzeOut, Unauthorized,
+        },
     },
 };
 
@@ -15,6 +20,7 @@ use crate::{
 pub enum Error {
     InvalidAmount,
     WitnessModeMismatch(u32, u32),
+    UnsupportedExtension(u32, u32),
 }
 
 impl fmt::Display for Error {
@@ -23,6 +29,23 @@ impl fmt::Display for Error {
             Error::InvalidAmount => write!(f, "Invalid amount"),
             Error::WitnessModeMismatch(expected, actual) =>
                 write!(f, "TZE witness builder returned a mode that did not match the mode with which the input was initially constructed: expected = {:?}, actual = {:?}", expected, actual),
+            Error::UnsupportedExtension(extension_id, mode) =>
+                write!(f, "The (extension_id, mode) pair ({:?}, {:?}) is not active for the consensus branch targeted by this builder.", extension_id, mode),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FeeError<E> {
+    Build(Error),
+    Rule(E),
+}
+
+impl<E: fmt::Display> fmt::Display for FeeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeeError::Build(e) => write!(f, "{}", e),
+            FeeError::Rule(e) => write!(f, "{}", e),
         }
     }
 }
@@ -30,38 +53,79 @@ impl fmt::Display for Error {
 #[allow(clippy::type_complexity)]
 struct TzeSigner<'a, BuildCtx> {
     prevout: TzeOut,
-    builder: Box<dyn FnOnce(&BuildCtx) -> Result<(u32, Vec<u8>), Error> + 'a>,
+    builder: Box<dyn Fn(&BuildCtx) -> Result<(u32, Vec<u8>), Error> + 'a>,
 }
 
-pub struct TzeBuilder<'a, BuildCtx> {
+/// Typestate marker indicating that a [`TzeBuilder`] may still have inputs
+/// and outputs added to it.
+pub struct Unsealed;
+
+/// Typestate marker indicating that a [`TzeBuilder`]'s inputs and outputs
+/// have been fixed, and that witnesses for its inputs may now be computed.
+pub struct SetWitnesses;
+
+pub struct TzeBuilder<'a, BuildCtx, S = Unsealed> {
+    extensions: HashMap<u32, HashSet<u32>>,
     signers: Vec<TzeSigner<'a, BuildCtx>>,
     vin: Vec<TzeIn<Unauthorized>>,
     vout: Vec<TzeOut>,
+    _state: PhantomData<S>,
 }
 
-impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx> {
-    pub fn empty() -> Self {
+impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx, Unsealed> {
+    /// Constructs a new empty builder that will only accept TZE inputs and
+    /// outputs for `(extension_id, mode)` pairs present in `extensions` —
+    /// the set of extensions and modes that are active for the consensus
+    /// branch being targeted. This mirrors the way the upstream extension
+    /// dispatch resolves an extension by id before evaluating any of its
+    /// preconditions, but catches an unsupported extension at build time
+    /// rather than at verification time.
+    pub fn empty(extensions: HashMap<u32, HashSet<u32>>) -> Self {
         TzeBuilder {
+            extensions,
             signers: vec![],
             vin: vec![],
             vout: vec![],
+            _state: PhantomData,
+        }
+    }
+
+    fn check_supported(&self, extension_id: u32, mode: u32) -> Result<(), Error> {
+        if self
+            .extensions
+            .get(&extension_id)
+            .map_or(false, |modes| modes.contains(&mode))
+        {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedExtension(extension_id, mode))
         }
     }
 
+    /// `witness_builder` must be side-effect-free and safe to invoke more
+    /// than once with the same `BuildCtx`: besides the call made when the
+    /// sealed builder's witnesses are finally created, `fee` may invoke it
+    /// ahead of time purely to measure the size of the witness it will
+    /// produce.
     pub fn add_input<WBuilder, W: ToPayload>(
         &mut self,
         extension_id: u32,
         mode: u32,
         (outpoint, prevout): (OutPoint, TzeOut),
         witness_builder: WBuilder,
-    ) where
-        WBuilder: 'a + FnOnce(&BuildCtx) -> Result<W, Error>,
+    ) -> Result<(), Error>
+    where
+        WBuilder: 'a + Fn(&BuildCtx) -> Result<W, Error>,
     {
+        self.check_supported(extension_id, mode)?;
+
         self.vin.push(TzeIn::new(outpoint, extension_id, mode));
         self.signers.push(TzeSigner {
             prevout,
             builder: Box::new(move |ctx| witness_builder(&ctx).map(|x| x.to_payload())),
         });
+
+        Ok(())
     }
 
     pub fn add_output<G: ToPayload>(
@@ -75,6 +139,8 @@ impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx> {
         }
 
         let (mode, payload) = guarded_by.to_payload();
+        self.check_supported(extension_id, mode)?;
+
         self.vout.push(TzeOut {
             value,
             precondition: tze::Precondition {
@@ -87,6 +153,32 @@ impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx> {
         Ok(())
     }
 
+    /// Computes the surplus of TZE input value over TZE output value (after
+    /// accounting for `fee`) and appends a single TZE output guarded by
+    /// `guarded_by` carrying exactly that remainder, so that the resulting
+    /// bundle balances.
+    ///
+    /// Returns the change amount, so that the caller can record it alongside
+    /// the change outputs it creates for the transparent and shielded value
+    /// pools.
+    pub fn add_change_output<G: ToPayload>(
+        &mut self,
+        extension_id: u32,
+        guarded_by: &G,
+        fee: Amount,
+    ) -> Result<Amount, Error> {
+        let change = (self.value_balance().ok_or(Error::InvalidAmount)? - fee)
+            .ok_or(Error::InvalidAmount)?;
+
+        if change.is_negative() {
+            return Err(Error::InvalidAmount);
+        }
+
+        self.add_output(extension_id, change, guarded_by)?;
+
+        Ok(change)
+    }
+
     pub fn value_balance(&self) -> Option<Amount> {
         self.signers
             .iter()
@@ -99,6 +191,69 @@ impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx> {
                 .sum::<Option<Amount>>()?
     }
 
+    /// Computes the marginal fee contribution of the TZE inputs and outputs
+    /// added to this builder so far, under the given fee rule.
+    ///
+    /// The fee is computed from the full encoded size of each `TzeIn`/`TzeOut`
+    /// (see `fees::tze_in_size`/`fees::tze_out_size`) rather than a flat
+    /// per-component charge, so that inputs or outputs with large
+    /// preconditions are priced accordingly. Since the witness payload for
+    /// an input is not known until its witness builder runs, this requires
+    /// `mtx` so that each input's actual witness can be produced and
+    /// measured; a witness builder must therefore be safe to invoke more
+    /// than once (it will be called again by `create_witnesses` or
+    /// `build_authorized` once the bundle is sealed).
+    pub fn fee<R: FeeRule>(
+        &self,
+        mtx: &BuildCtx,
+        rule: &R,
+    ) -> Result<Amount, FeeError<R::Error>> {
+        let total_input_size = self
+            .signers
+            .iter()
+            .map(|signer| {
+                let (_, payload) = (signer.builder)(mtx).map_err(FeeError::Build)?;
+                Ok(fees::tze_in_size(payload.len()))
+            })
+            .sum::<Result<usize, FeeError<R::Error>>>()?;
+
+        let total_output_size = self
+            .vout
+            .iter()
+            .map(|tzout| fees::tze_out_size(tzout.precondition.payload.len()))
+            .sum();
+
+        rule.fee_required(
+            self.signers.len(),
+            total_input_size,
+            self.vout.len(),
+            total_output_size,
+        )
+        .map_err(FeeError::Rule)
+    }
+
+    /// Freezes the set of TZE inputs and outputs added to this builder so
+    /// far, fixing their ordering and transitioning to a state in which
+    /// witnesses for the inputs may be computed.
+    ///
+    /// Once sealed, a builder no longer exposes `add_input`/`add_output`;
+    /// this prevents a caller from mutating the `vin`/`vout` vectors after
+    /// `create_witnesses` has measured them, which would otherwise allow the
+    /// mode/commitment checks performed there to be bypassed. `build` is
+    /// likewise only available once sealed, so that a caller cannot emit a
+    /// bundle whose `vin`/`vout` may still change.
+    pub fn seal(self) -> TzeBuilder<'a, BuildCtx, SetWitnesses> {
+        TzeBuilder {
+            extensions: self.extensions,
+            signers: self.signers,
+            vin: self.vin,
+            vout: self.vout,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx, SetWitnesses> {
     pub fn build(&self) -> Option<Bundle<Unauthorized>> {
         if self.vin.is_empty() && self.vout.is_empty() {
             None
@@ -138,4 +293,143 @@ impl<'a, BuildCtx> TzeBuilder<'a, BuildCtx> {
             Ok(Some(payloads))
         }
     }
+
+    /// Computes the witness for each TZE input and installs it directly,
+    /// producing a fully [`Authorized`] bundle rather than a detached
+    /// `Vec<AuthData>` that the caller would otherwise have to re-zip with
+    /// the `vin` returned by `build`.
+    ///
+    /// As with `create_witnesses`, each witness's reported mode is checked
+    /// against the mode with which the corresponding input was originally
+    /// added; this guarantees the witness payloads in the resulting bundle
+    /// can never drift out of alignment with their inputs. Each `TzeIn` is
+    /// built directly from its own `(outpoint, witness)` pair by a single
+    /// positional zip over `self.vin`, so the pairing is explicit in this
+    /// function rather than depending on the call order of some other
+    /// mechanism.
+    pub fn build_authorized(self, mtx: &BuildCtx) -> Result<Option<Bundle<Authorized>>, Error> {
+        if self.vin.is_empty() && self.vout.is_empty() {
+            return Ok(None);
+        }
+
+        let vout = self.vout;
+        let vin = self
+            .signers
+            .into_iter()
+            .zip(self.vin.into_iter())
+            .map(|(signer, tzein)| {
+                let (mode, payload) = (signer.builder)(mtx)?;
+                let input_mode = tzein.witness.mode;
+                if mode != input_mode {
+                    return Err(Error::WitnessModeMismatch(input_mode, mode));
+                }
+
+                Ok(TzeIn {
+                    outpoint: tzein.outpoint,
+                    witness: tze::AuthData(payload),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Some(Bundle { vin, vout }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{Error, TzeBuilder};
+    use crate::{
+        extensions::transparent::ToPayload,
+        transaction::components::{
+            amount::Amount,
+            tze::{OutPoint, Precondition, TzeOut},
+        },
+    };
+
+    #[derive(Clone)]
+    struct TestPayload(u32, Vec<u8>);
+
+    impl ToPayload for TestPayload {
+        fn to_payload(&self) -> (u32, Vec<u8>) {
+            (self.0, self.1.clone())
+        }
+    }
+
+    fn registry() -> HashMap<u32, HashSet<u32>> {
+        let mut extensions = HashMap::new();
+        extensions.insert(0, vec![0].into_iter().collect());
+        extensions
+    }
+
+    fn prevout(value: Amount) -> TzeOut {
+        TzeOut {
+            value,
+            precondition: Precondition {
+                extension_id: 0,
+                mode: 0,
+                payload: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn add_input_rejects_unregistered_extension() {
+        let mut builder = TzeBuilder::<()>::empty(registry());
+
+        let result = builder.add_input(
+            1,
+            0,
+            (OutPoint::new([0; 32], 0), prevout(Amount::zero())),
+            |_: &()| Ok(TestPayload(0, vec![])),
+        );
+
+        assert_eq!(result, Err(Error::UnsupportedExtension(1, 0)));
+    }
+
+    #[test]
+    fn add_output_rejects_unregistered_mode() {
+        let mut builder = TzeBuilder::<()>::empty(registry());
+
+        let result = builder.add_output(0, Amount::zero(), &TestPayload(1, vec![]));
+
+        assert_eq!(result, Err(Error::UnsupportedExtension(0, 1)));
+    }
+
+    #[test]
+    fn add_change_output_rejects_negative_remainder() {
+        let mut builder = TzeBuilder::<()>::empty(registry());
+        builder
+            .add_input(
+                0,
+                0,
+                (OutPoint::new([0; 32], 0), prevout(Amount::zero())),
+                |_: &()| Ok(TestPayload(0, vec![])),
+            )
+            .unwrap();
+
+        let result =
+            builder.add_change_output(0, &TestPayload(0, vec![]), Amount::from_u64(1).unwrap());
+
+        assert_eq!(result, Err(Error::InvalidAmount));
+    }
+
+    #[test]
+    fn build_authorized_detects_witness_mode_mismatch() {
+        let mut builder = TzeBuilder::<()>::empty(registry());
+        builder
+            .add_input(
+                0,
+                0,
+                (OutPoint::new([0; 32], 0), prevout(Amount::zero())),
+                // The witness builder reports mode 1, but the input was added with mode 0.
+                |_: &()| Ok(TestPayload(1, vec![])),
+            )
+            .unwrap();
+
+        let result = builder.seal().build_authorized(&());
+
+        assert_eq!(result, Err(Error::WitnessModeMismatch(0, 1)));
+    }
 }
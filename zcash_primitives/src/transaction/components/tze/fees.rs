@@ -0,0 +1,116 @@
+//! Types related to fee calculation for TZE inputs and outputs.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::transaction::components::amount::Amount;
+
+/// A trait that represents a fee rule for determining the amount that a TZE
+/// bundle contributes to the transaction's total fee, given the number and
+/// total serialized size (see [`tze_in_size`] and [`tze_out_size`]) of the
+/// TZE inputs and outputs being added to the transaction.
+///
+/// Implementations are driven off the encoded size of each `TzeIn`/`TzeOut`
+/// rather than a flat per-component fee, since TZE witness and precondition
+/// payloads are variable-length and a flat fee would mis-price large
+/// preconditions.
+pub trait FeeRule {
+    /// The error type returned by this rule when a valid fee cannot be
+    /// computed for the given arguments.
+    type Error;
+
+    /// Computes the fee required for a TZE bundle consisting of the given
+    /// number of inputs and outputs, with the given total serialized sizes
+    /// (in bytes) of the encoded inputs and outputs respectively (see
+    /// [`tze_in_size`] and [`tze_out_size`]).
+    fn fee_required(
+        &self,
+        num_inputs: usize,
+        total_input_size: usize,
+        num_outputs: usize,
+        total_output_size: usize,
+    ) -> Result<Amount, Self::Error>;
+}
+
+/// The error produced by [`MarginalFeeRule`] when the computed fee overflows
+/// the range of a valid [`Amount`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct AmountOverflow;
+
+impl fmt::Display for AmountOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TZE fee computation overflowed the valid amount range")
+    }
+}
+
+/// A `FeeRule` implementation that charges a constant number of zats per
+/// byte of serialized TZE input/output data, regardless of how those bytes
+/// are distributed between inputs and outputs.
+pub struct MarginalFeeRule {
+    zats_per_byte: i64,
+}
+
+impl MarginalFeeRule {
+    /// Constructs a `MarginalFeeRule` that charges `zats_per_byte` for each
+    /// byte of encoded TZE input and output data.
+    pub fn new(zats_per_byte: i64) -> Self {
+        MarginalFeeRule { zats_per_byte }
+    }
+}
+
+impl FeeRule for MarginalFeeRule {
+    type Error = AmountOverflow;
+
+    fn fee_required(
+        &self,
+        _num_inputs: usize,
+        total_input_size: usize,
+        _num_outputs: usize,
+        total_output_size: usize,
+    ) -> Result<Amount, Self::Error> {
+        let total_size: i64 = total_input_size
+            .checked_add(total_output_size)
+            .and_then(|n| i64::try_from(n).ok())
+            .ok_or(AmountOverflow)?;
+
+        let total_fee = total_size.checked_mul(self.zats_per_byte).ok_or(AmountOverflow)?;
+
+        Amount::from_i64(total_fee).map_err(|_| AmountOverflow)
+    }
+}
+
+/// Returns the number of bytes used by the CompactSize encoding of a length
+/// value `n`, per the Bitcoin/Zcash wire format (1, 3, 5, or 9 bytes
+/// depending on magnitude).
+fn compact_size_len(n: usize) -> usize {
+    if n < 0xfd {
+        1
+    } else if n <= 0xffff {
+        3
+    } else if n <= 0xffff_ffff {
+        5
+    } else {
+        9
+    }
+}
+
+/// Size in bytes of a serialized transparent `OutPoint` (a 32-byte txid plus
+/// a 4-byte output index).
+const OUTPOINT_SIZE: usize = 36;
+
+/// Size in bytes of a serialized `Amount` value.
+const VALUE_SIZE: usize = 8;
+
+/// Returns the number of bytes used to encode a `TzeIn`: its outpoint, plus
+/// its 4-byte `extension_id` and 4-byte `mode`, plus a CompactSize-prefixed
+/// witness payload of `payload_len` bytes.
+pub(crate) fn tze_in_size(payload_len: usize) -> usize {
+    OUTPOINT_SIZE + 4 + 4 + compact_size_len(payload_len) + payload_len
+}
+
+/// Returns the number of bytes used to encode a `TzeOut`: its 8-byte
+/// `value`, plus its 4-byte `extension_id` and 4-byte `mode`, plus a
+/// CompactSize-prefixed precondition payload of `payload_len` bytes.
+pub(crate) fn tze_out_size(payload_len: usize) -> usize {
+    VALUE_SIZE + 4 + 4 + compact_size_len(payload_len) + payload_len
+}